@@ -1,22 +1,82 @@
 use futures::{Stream, StreamExt};
-use leptos::{use_context, RuntimeId, ScopeId};
+use http::{HeaderName, HeaderValue};
+use leptos::{use_context, RuntimeId, Scope, ScopeId};
 use leptos_config::LeptosOptions;
 use leptos_meta::MetaContext;
 
 extern crate tracing;
 
+/// A cryptographic nonce ("number used once") that can be passed to the
+/// `script-src` directive of a Content-Security-Policy header, and is
+/// stamped onto every inline `<script>` this crate generates so that the
+/// hydration bootstrap and autoreload script continue to run under a
+/// strict CSP that doesn't allow `unsafe-inline`.
+///
+/// To use this, generate a nonce (e.g. with a cryptographically secure
+/// RNG, base64-encoded) per request, provide it to the `Scope` via
+/// [`leptos::provide_context`], and set the matching `script-src
+/// 'nonce-...'` header on the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nonce(pub String);
+
+impl std::fmt::Display for Nonce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Returns a `nonce="..."` attribute (with a leading space) if a [`Nonce`]
+/// has been provided via context, or an empty string otherwise.
+fn nonce_attr(cx: Scope) -> String {
+    use_context::<Nonce>(cx)
+        .map(|nonce| format!(" nonce=\"{nonce}\""))
+        .unwrap_or_default()
+}
+
+// Because wasm-pack adds _bg to the end of the WASM filename, and we want to mantain compatibility with it's default options
+// we add _bg to the wasm files if cargo-leptos doesn't set the env var LEPTOS_OUTPUT_NAME
+// Otherwise we need to add _bg because wasm_pack always does. This is not the same as options.output_name, which is set regardless
+//
+// This is shared by every helper that emits a wasm URL (`html_parts`,
+// `html_parts_separated`, `preload_links`) so they can never resolve the
+// filename differently and point a `<link>` tag and an Early-Hints `Link`
+// header at two different URLs for the same bundle.
+fn wasm_output_name(options: &LeptosOptions) -> String {
+    let mut wasm_output_name = options.output_name.clone();
+    if std::env::var("LEPTOS_OUTPUT_NAME").is_err() {
+        wasm_output_name.push_str("_bg");
+    }
+    wasm_output_name
+}
+
 #[tracing::instrument(level = "trace", fields(error), skip_all)]
-fn autoreload(options: &LeptosOptions) -> String {
-    let site_ip = std::env::var("LEPTOS_SITE_EXTERNAL_HOSTNAME")
-        .unwrap_or(options.site_addr.ip().to_string());
-    let reload_port = std::env::var("LEPTOS_SITE_EXTERNAL_PORT")
-        .unwrap_or(options.reload_port.to_string());
+fn autoreload(cx: Scope, options: &LeptosOptions) -> String {
+    // Only pin the host/port server-side if they were explicitly configured.
+    // Otherwise defer to `window.location.host` at runtime, since
+    // `options.site_addr` is the server's *bind* address, which is often
+    // unreachable from the browser behind a reverse proxy.
+    let site_ip = std::env::var("LEPTOS_SITE_EXTERNAL_HOSTNAME").ok();
+    let reload_port = std::env::var("LEPTOS_SITE_EXTERNAL_PORT").ok();
+    let reload_secure = matches!(
+        std::env::var("LEPTOS_SITE_RELOAD_SECURE").as_deref(),
+        Ok("true") | Ok("1")
+    );
+    let ws_host = match (&site_ip, &reload_port) {
+        (Some(ip), Some(port)) => format!("'{ip}:{port}'"),
+        (Some(ip), None) => {
+            format!("'{ip}' + (window.location.port ? ':' + window.location.port : '')")
+        }
+        (None, Some(port)) => format!("window.location.hostname + ':{port}'"),
+        (None, None) => "window.location.host".to_string(),
+    };
+    let nonce = nonce_attr(cx);
     match std::env::var("LEPTOS_WATCH").is_ok() {
         true => format!(
             r#"
-                <script crossorigin="">(function () {{
+                <script crossorigin=""{nonce}>(function () {{
                     {}
-                    var ws = new WebSocket('ws://{site_ip}:{reload_port}/live_reload');
+                    var ws_scheme = {reload_secure} || window.location.protocol == "https:" ? "wss" : "ws";
+                    var ws = new WebSocket(ws_scheme + '://' + {ws_host} + '/live_reload');
                     ws.onmessage = (ev) => {{
                         let msg = JSON.parse(ev.data);
                         if (msg.all) window.location.reload();
@@ -46,21 +106,16 @@ fn autoreload(options: &LeptosOptions) -> String {
 }
 #[tracing::instrument(level = "trace", fields(error), skip_all)]
 pub fn html_parts(
+    cx: Scope,
     options: &LeptosOptions,
     meta: Option<&MetaContext>,
 ) -> (String, &'static str) {
     let pkg_path = &options.site_pkg_dir;
     let output_name = &options.output_name;
+    let wasm_output_name = wasm_output_name(options);
 
-    // Because wasm-pack adds _bg to the end of the WASM filename, and we want to mantain compatibility with it's default options
-    // we add _bg to the wasm files if cargo-leptos doesn't set the env var LEPTOS_OUTPUT_NAME at compile time
-    // Otherwise we need to add _bg because wasm_pack always does.
-    let mut wasm_output_name = output_name.clone();
-    if std::option_env!("LEPTOS_OUTPUT_NAME").is_none() {
-        wasm_output_name.push_str("_bg");
-    }
-
-    let leptos_autoreload = autoreload(options);
+    let leptos_autoreload = autoreload(cx, options);
+    let nonce = nonce_attr(cx);
 
     let html_metadata =
         meta.and_then(|mc| mc.html.as_string()).unwrap_or_default();
@@ -72,7 +127,7 @@ pub fn html_parts(
                     <meta name="viewport" content="width=device-width, initial-scale=1"/>
                     <link rel="modulepreload" href="/{pkg_path}/{output_name}.js">
                     <link rel="preload" href="/{pkg_path}/{wasm_output_name}.wasm" as="fetch" type="application/wasm" crossorigin="">
-                    <script type="module">import init, {{ hydrate }} from '/{pkg_path}/{output_name}.js'; init('/{pkg_path}/{wasm_output_name}.wasm').then(hydrate);</script>
+                    <script type="module"{nonce}>import init, {{ hydrate }} from '/{pkg_path}/{output_name}.js'; init('/{pkg_path}/{wasm_output_name}.wasm').then(hydrate);</script>
                     {leptos_autoreload}
                     "#
     );
@@ -82,21 +137,16 @@ pub fn html_parts(
 
 #[tracing::instrument(level = "trace", fields(error), skip_all)]
 pub fn html_parts_separated(
+    cx: Scope,
     options: &LeptosOptions,
     meta: Option<&MetaContext>,
 ) -> (String, &'static str) {
     let pkg_path = &options.site_pkg_dir;
     let output_name = &options.output_name;
+    let wasm_output_name = wasm_output_name(options);
 
-    // Because wasm-pack adds _bg to the end of the WASM filename, and we want to mantain compatibility with it's default options
-    // we add _bg to the wasm files if cargo-leptos doesn't set the env var LEPTOS_OUTPUT_NAME
-    // Otherwise we need to add _bg because wasm_pack always does. This is not the same as options.output_name, which is set regardless
-    let mut wasm_output_name = output_name.clone();
-    if std::env::var("LEPTOS_OUTPUT_NAME").is_err() {
-        wasm_output_name.push_str("_bg");
-    }
-
-    let leptos_autoreload = autoreload(options);
+    let leptos_autoreload = autoreload(cx, options);
+    let nonce = nonce_attr(cx);
 
     let html_metadata =
         meta.and_then(|mc| mc.html.as_string()).unwrap_or_default();
@@ -113,7 +163,7 @@ pub fn html_parts_separated(
                     {head}
                     <link rel="modulepreload" href="/{pkg_path}/{output_name}.js">
                     <link rel="preload" href="/{pkg_path}/{wasm_output_name}.wasm" as="fetch" type="application/wasm" crossorigin="">
-                    <script type="module">import init, {{ hydrate }} from '/{pkg_path}/{output_name}.js'; init('/{pkg_path}/{wasm_output_name}.wasm').then(hydrate);</script>
+                    <script type="module"{nonce}>import init, {{ hydrate }} from '/{pkg_path}/{output_name}.js'; init('/{pkg_path}/{wasm_output_name}.wasm').then(hydrate);</script>
                     {leptos_autoreload}
                     "#
     );
@@ -121,6 +171,39 @@ pub fn html_parts_separated(
     (head, tail)
 }
 
+/// Builds the `Link` header values for the JS/WASM bundle that [`html_parts`]
+/// and [`html_parts_separated`] otherwise preload via `<link>` tags in the
+/// `<head>`. Server integrations can send these as an informational `103
+/// Early Hints` response so the browser starts fetching the bundle before
+/// the first bytes of HTML (e.g. while `build_async_response` is still
+/// waiting on suspended resources). The in-head tags are still emitted as a
+/// fallback for clients and proxies that don't support Early Hints.
+#[tracing::instrument(level = "trace", fields(error), skip_all)]
+pub fn preload_links(
+    options: &LeptosOptions,
+) -> Vec<(HeaderName, HeaderValue)> {
+    let pkg_path = &options.site_pkg_dir;
+    let output_name = &options.output_name;
+    let wasm_output_name = wasm_output_name(options);
+
+    vec![
+        (
+            HeaderName::from_static("link"),
+            HeaderValue::from_str(&format!(
+                "</{pkg_path}/{output_name}.js>; rel=modulepreload"
+            ))
+            .expect("file names and paths should be valid header values"),
+        ),
+        (
+            HeaderName::from_static("link"),
+            HeaderValue::from_str(&format!(
+                "</{pkg_path}/{wasm_output_name}.wasm>; rel=preload; as=fetch; crossorigin"
+            ))
+            .expect("file names and paths should be valid header values"),
+        ),
+    ]
+}
+
 #[tracing::instrument(level = "trace", fields(error), skip_all)]
 pub async fn build_async_response(
     stream: impl Stream<Item = String> + 'static,
@@ -135,8 +218,11 @@ pub async fn build_async_response(
     }
 
     let cx = leptos::Scope { runtime, id: scope };
-    let (head, tail) =
-        html_parts_separated(options, use_context::<MetaContext>(cx).as_ref());
+    let (head, tail) = html_parts_separated(
+        cx,
+        options,
+        use_context::<MetaContext>(cx).as_ref(),
+    );
 
     // in async, we load the meta content *now*, after the suspenses have resolved
     let meta = use_context::<MetaContext>(cx);
@@ -153,3 +239,143 @@ pub async fn build_async_response(
 
     format!("{head}{head_meta}</head><body{body_meta}>{buf}{tail}")
 }
+
+/// Escapes a string for embedding as a JS double-quoted string literal
+/// inside an inline `<script>` this module generates. Also escapes `/` so
+/// that an embedded `</script>` can't terminate the surrounding tag early.
+fn js_string_literal(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '/' => out.push_str("\\/"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Disposes a Leptos runtime when dropped.
+///
+/// Runtimes aren't RAII on their own -- they only leave the global runtime
+/// map via an explicit call to `dispose()`. A plain `runtime.dispose()` at
+/// the end of an async block only runs if that block is polled to
+/// completion, but a streaming HTTP body is routinely dropped early (the
+/// client disconnects mid-response), so wrapping the runtime in this guard
+/// and moving it into the stream ensures disposal happens on early drop
+/// too, not just on normal completion.
+struct DisposeRuntimeOnDrop(RuntimeId);
+
+impl Drop for DisposeRuntimeOnDrop {
+    fn drop(&mut self) {
+        self.0.dispose();
+    }
+}
+
+/// Wraps a `Stream` together with a value that should be dropped -- not
+/// just run to completion -- whenever the stream itself is dropped.
+struct WithGuard<S, G> {
+    stream: S,
+    _guard: G,
+}
+
+impl<S: Stream + Unpin, G: Unpin> Stream for WithGuard<S, G> {
+    type Item = S::Item;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        std::pin::Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+/// A progressive variant of [`build_async_response`] for server integrations
+/// that want to start sending bytes before suspended resources resolve.
+///
+/// `build_async_response` has to buffer the whole body stream before it can
+/// produce anything, because the meta context (title, links, etc. set by
+/// `leptos_meta` components) is only fully populated once every suspense has
+/// resolved. This instead flushes the static `<head>` opener -- doctype,
+/// charset, the modulepreload/preload tags, and the autoreload script, none
+/// of which depend on resolved data -- and `<body>` immediately, streams
+/// body chunks as they arrive, and defers only the meta-dependent dehydration
+/// to a small inline script appended at the end, which relocates the
+/// resolved `<title>`/`<link>` tags into `<head>` and applies the resolved
+/// `<html>`/`<body>` attributes (e.g. `lang`, `class` set via `<Html/>` and
+/// `<Body/>`) -- the same relocate-on-resolve trick `leptos_meta` already
+/// uses to hydrate on the client.
+#[tracing::instrument(level = "trace", fields(error), skip_all)]
+pub fn build_async_response_stream(
+    stream: impl Stream<Item = String> + 'static,
+    options: &LeptosOptions,
+    runtime: RuntimeId,
+    scope: ScopeId,
+) -> impl Stream<Item = String> {
+    let cx = leptos::Scope { runtime, id: scope };
+    // The meta context isn't populated yet -- components haven't rendered --
+    // so the opening chunk can only contain the parts that never depend on
+    // resolved data.
+    let (head, _) = html_parts(cx, options, None);
+    let opener = futures::stream::once(async move { format!("{head}</head><body>") });
+
+    let closer = futures::stream::once(async move {
+        // Now that every suspense has resolved, the meta context is fully
+        // populated; relocate it into <head> and onto <html>/<body> via an
+        // inline script rather than delaying the response for it.
+        let meta = use_context::<MetaContext>(cx);
+        let html_attrs = meta
+            .as_ref()
+            .and_then(|meta| meta.html.as_string())
+            .unwrap_or_default();
+        let head_meta = meta
+            .as_ref()
+            .map(|meta| meta.dehydrate())
+            .unwrap_or_default();
+        let body_attrs = meta
+            .as_ref()
+            .and_then(|meta| meta.body.as_string())
+            .unwrap_or_default();
+        let nonce = nonce_attr(cx);
+
+        let head_meta = js_string_literal(&head_meta);
+        let html_attrs = js_string_literal(&html_attrs);
+        let body_attrs = js_string_literal(&body_attrs);
+
+        // `<html>`/`<head>`/`<body>` start tags are dropped by the HTML
+        // parser when parsed as `<template>` fragment content, so the
+        // attribute strings are parsed onto a `<div>` instead, then copied
+        // across -- a `<body ...>`/`<html ...>` root here would silently
+        // leave `.content.firstChild` null and drop every attribute.
+        format!(
+            r#"<script{nonce}>(function () {{
+                    function relocateAttrs(target, attrs) {{
+                        var tpl = document.createElement('template');
+                        tpl.innerHTML = '<div' + attrs + '></div>';
+                        var el = tpl.content.firstChild;
+                        if (el) {{
+                            for (const attr of el.attributes) {{
+                                target.setAttribute(attr.name, attr.value);
+                            }}
+                        }}
+                    }}
+                    var headTpl = document.createElement('template');
+                    headTpl.innerHTML = {head_meta};
+                    document.head.append(...headTpl.content.childNodes);
+                    relocateAttrs(document.documentElement, {html_attrs});
+                    relocateAttrs(document.body, {body_attrs});
+                }})()</script></body></html>"#
+        )
+    });
+
+    WithGuard {
+        stream: Box::pin(opener.chain(stream).chain(closer))
+            as std::pin::Pin<Box<dyn Stream<Item = String>>>,
+        _guard: DisposeRuntimeOnDrop(runtime),
+    }
+}